@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use sillad::{tcp::TcpDialer, Dialer};
+use sillad_sosistab3::{datagram::SosistabDatagram, dialer::SosistabDialer, mux::MuxSession, Cookie};
+
+use crate::command::run_transfer;
+
+/// Runs the client side of the prototest: connects to `connect` and runs one throughput test per
+/// requested stream, logging each one's result.
+///
+/// `sosistab3` obfuscates the transport under the given cookie when set. `datagram` switches the
+/// transport to [`SosistabDatagram`] over UDP instead of a TCP stream, in which case `streams` is
+/// ignored since a datagram association has no notion of multiplexed sub-streams. `streams` fans
+/// the connection out into that many concurrent [`MuxSession`] streams for more realistic
+/// multi-stream benchmarking.
+pub async fn client_main(connect: SocketAddr, sosistab3: Option<String>, datagram: bool, streams: usize) -> anyhow::Result<()> {
+    let cookie = sosistab3.as_deref().map(Cookie::new);
+
+    if datagram {
+        let cookie = cookie.context("--datagram requires a --sosistab3 cookie")?;
+        let local: SocketAddr = if connect.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let assoc = SosistabDatagram::connect(local, connect, cookie).await?;
+        let mut pipe = assoc.into_pipe();
+        return run_transfer(&mut pipe, "datagram").await.context("datagram transfer failed");
+    }
+
+    let tcp_dialer = TcpDialer { dest_addr: connect };
+    let mux = match cookie {
+        Some(cookie) => MuxSession::new(SosistabDialer::new(tcp_dialer, cookie).dial().await?, true),
+        None => MuxSession::new(tcp_dialer.dial().await?, true),
+    };
+
+    let mut handles = Vec::with_capacity(streams);
+    for i in 0..streams {
+        let mut stream = mux.open_stream();
+        handles.push(smolscale::spawn(
+            async move { run_transfer(&mut stream, &format!("stream-{i}")).await },
+        ));
+    }
+    for handle in handles {
+        handle.await.context("stream transfer failed")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use std::time::Instant;
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size, in bytes, of the payload exchanged by one throughput test run.
+const TRANSFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// The client side of one throughput test: writes `TRANSFER_SIZE` bytes, waits for the server to
+/// echo them back, and logs the achieved throughput. `label` distinguishes concurrent runs (e.g.
+/// one per `--streams` stream) in the log output.
+pub async fn run_transfer<P: AsyncRead + AsyncWrite + Unpin>(pipe: &mut P, label: &str) -> std::io::Result<()> {
+    let start = Instant::now();
+    let out = vec![0u8; TRANSFER_SIZE];
+    pipe.write_all(&out).await?;
+    pipe.flush().await?;
+    let mut back = vec![0u8; TRANSFER_SIZE];
+    pipe.read_exact(&mut back).await?;
+    let elapsed = start.elapsed();
+    let mbps = (TRANSFER_SIZE as f64 * 8.0 / 1e6) / elapsed.as_secs_f64();
+    tracing::info!(label, ?elapsed, mbps, "transfer complete");
+    Ok(())
+}
+
+/// The server side of one throughput test: reads back exactly what [`run_transfer`] sends, then
+/// echoes it, so the client's round trip measures real send-and-receive throughput rather than
+/// just one direction.
+pub async fn echo_transfer<P: AsyncRead + AsyncWrite + Unpin>(pipe: &mut P) -> std::io::Result<()> {
+    let mut buf = vec![0u8; TRANSFER_SIZE];
+    pipe.read_exact(&mut buf).await?;
+    pipe.write_all(&buf).await?;
+    pipe.flush().await?;
+    Ok(())
+}
@@ -35,6 +35,9 @@ struct ServerCmd {
     /// sosistab3 cookie for obfuscation
     #[argh(option, long = "sosistab3")]
     sosistab3: Option<String>,
+    /// use sosistab3's datagram transport (`SosistabDatagram`) over UDP instead of a TCP stream
+    #[argh(switch, long = "datagram")]
+    datagram: bool,
 }
 
 /// Start the client with a connection address.
@@ -47,6 +50,13 @@ struct ClientCmd {
     /// sosistab3 cookie for obfuscation
     #[argh(option, long = "sosistab3")]
     sosistab3: Option<String>,
+    /// use sosistab3's datagram transport (`SosistabDatagram`) over UDP instead of a TCP stream
+    #[argh(switch, long = "datagram")]
+    datagram: bool,
+    /// fan out this many concurrent `MuxSession` streams over the one connection, instead of a
+    /// single transfer, for more realistic multi-stream benchmarking
+    #[argh(option, long = "streams", default = "1")]
+    streams: usize,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -54,7 +64,7 @@ fn main() -> anyhow::Result<()> {
     let args: Args = argh::from_env();
 
     match args.subcommand {
-        Subcommand::Server(cmd) => smolscale::block_on(server_main(cmd.listen, cmd.sosistab3)),
-        Subcommand::Client(cmd) => smolscale::block_on(client_main(cmd.connect, cmd.sosistab3)),
+        Subcommand::Server(cmd) => smolscale::block_on(server_main(cmd.listen, cmd.sosistab3, cmd.datagram)),
+        Subcommand::Client(cmd) => smolscale::block_on(client_main(cmd.connect, cmd.sosistab3, cmd.datagram, cmd.streams)),
     }
 }
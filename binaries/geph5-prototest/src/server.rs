@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use sillad::{tcp::TcpListener, Listener, Pipe};
+use sillad_sosistab3::{datagram::SosistabDatagramListener, listener::SosistabListener, mux::MuxSession, Cookie};
+
+use crate::command::echo_transfer;
+
+/// Runs the server side of the prototest: accepts connections on `listen` and echoes back
+/// whatever each stream sends, so the client side can measure round-trip throughput.
+///
+/// `sosistab3` obfuscates the transport under the given cookie when set, matching the client's
+/// `--sosistab3` flag. `datagram` switches the transport from a TCP stream wrapped in
+/// [`sillad_sosistab3::SosistabPipe`] to [`sillad_sosistab3::datagram::SosistabDatagram`] over UDP.
+pub async fn server_main(listen: SocketAddr, sosistab3: Option<String>, datagram: bool) -> anyhow::Result<()> {
+    let cookie = sosistab3.as_deref().map(Cookie::new);
+
+    if datagram {
+        let cookie = cookie.context("--datagram requires a --sosistab3 cookie")?;
+        let listener = SosistabDatagramListener::listen(listen, cookie).await?;
+        loop {
+            let assoc = listener.accept().await?;
+            smolscale::spawn(async move {
+                let mut pipe = assoc.into_pipe();
+                if let Err(err) = echo_transfer(&mut pipe).await {
+                    tracing::warn!(?err, "datagram transfer failed");
+                }
+            })
+            .detach();
+        }
+    }
+
+    let tcp_listener = TcpListener::bind(listen).await?;
+    match cookie {
+        Some(cookie) => serve_streams(SosistabListener::new(tcp_listener, cookie)).await,
+        None => serve_streams(tcp_listener).await,
+    }
+}
+
+/// Accepts connections off `listener` forever, multiplexing each one with [`MuxSession`] so a
+/// client's `--streams` fan-out is handled transparently: a client that never opens more than one
+/// stream behaves exactly like a single unmultiplexed connection.
+async fn serve_streams<L: Listener>(mut listener: L) -> anyhow::Result<()>
+where
+    L::Output: Pipe + Unpin + Send + 'static,
+{
+    loop {
+        let pipe = listener.accept().await?;
+        smolscale::spawn(async move {
+            let mux = MuxSession::new(pipe, false);
+            while let Ok(mut stream) = mux.accept_stream().await {
+                smolscale::spawn(async move {
+                    if let Err(err) = echo_transfer(&mut stream).await {
+                        tracing::warn!(?err, "stream transfer failed");
+                    }
+                })
+                .detach();
+            }
+        })
+        .detach();
+    }
+}
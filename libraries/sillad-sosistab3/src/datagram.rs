@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use futures_util::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+use sillad::Pipe;
+use smol::net::UdpSocket;
+
+use crate::{dedup::Dedup, derived_shared_secret, pool, Cookie, ObfsParams};
+
+/// Length, in bytes, of the explicit per-packet nonce. Datagrams can arrive reordered or not at
+/// all, so (unlike the stream `State`) a counter shared between the two ends can't be used; each
+/// packet instead carries its own randomly-generated nonce.
+const NONCE_LEN: usize = 12;
+/// Length of the plaintext header used when `obfs_lengths` padding is on: a `u16` real-length
+/// field, mirroring the stream shaper's cell header.
+const HEADER_LEN: usize = 2;
+/// Bucket size, in bytes, that padded datagrams are rounded up to.
+const PAD_BUCKET: usize = 128;
+const MAX_DATAGRAM: usize = 65536;
+
+fn encrypt_packet(key: [u8; 32], params: ObfsParams, plain: &[u8]) -> Vec<u8> {
+    let nonce_bytes: [u8; NONCE_LEN] = rand::random();
+    let cipher = ChaCha20Poly1305::new((&key).into());
+    let cell = if params.obfs_lengths {
+        let padded_len = (HEADER_LEN + plain.len()).div_ceil(PAD_BUCKET) * PAD_BUCKET;
+        let mut cell = vec![0u8; padded_len];
+        cell[..HEADER_LEN].copy_from_slice(&(plain.len() as u16).to_be_bytes());
+        cell[HEADER_LEN..HEADER_LEN + plain.len()].copy_from_slice(plain);
+        cell
+    } else {
+        plain.to_vec()
+    };
+    let ct = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), cell.as_slice())
+        .expect("chacha20poly1305 encryption cannot fail");
+    let mut out = Vec::with_capacity(NONCE_LEN + ct.len());
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(&ct);
+    out
+}
+
+fn decrypt_packet(
+    key: [u8; 32],
+    params: ObfsParams,
+    dedup: &mut Dedup,
+    raw: &[u8],
+) -> io::Result<Vec<u8>> {
+    if raw.len() < NONCE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than a nonce"));
+    }
+    let (nonce_bytes, ct) = raw.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new((&key).into());
+    let plain = cipher
+        .decrypt(Nonce::from_slice(nonce_bytes), ct)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "packet failed to authenticate"))?;
+    // Dedup only after authentication succeeds: checking first would let forged, never-authenticating
+    // packets consume slots in the bounded replay cache, letting an off-path attacker flood junk to
+    // evict genuine packet hashes and reopen a replay window.
+    if !dedup.check_and_insert(*blake3::hash(raw).as_bytes()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "replayed packet"));
+    }
+    if params.obfs_lengths {
+        if plain.len() < HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "padded cell shorter than its header"));
+        }
+        let real_len = u16::from_be_bytes(plain[..HEADER_LEN].try_into().unwrap()) as usize;
+        if HEADER_LEN + real_len > plain.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "padded cell's length header is out of range",
+            ));
+        }
+        Ok(plain[HEADER_LEN..HEADER_LEN + real_len].to_vec())
+    } else {
+        Ok(plain)
+    }
+}
+
+/// A single obfuscated, encrypted datagram association: every `send` becomes one UDP packet,
+/// every successfully-authenticated, non-replayed incoming packet becomes one `recv`.
+pub struct SosistabDatagram {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    send_key: [u8; 32],
+    shared_secret: [u8; 32],
+    params: ObfsParams,
+    inbound: async_channel::Receiver<Vec<u8>>,
+}
+
+impl SosistabDatagram {
+    /// Connects to `peer`, ready to exchange obfuscated datagrams under `cookie`.
+    pub async fn connect(local: SocketAddr, peer: SocketAddr, cookie: Cookie) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        socket.connect(peer).await?;
+        let (tx, rx) = async_channel::unbounded();
+        let recv_socket = socket.clone();
+        let recv_key = cookie.derive_key(true);
+        let params = cookie.params();
+        smolscale::spawn(async move {
+            let mut dedup = Dedup::default();
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Ok(n) = recv_socket.recv(&mut buf).await {
+                if let Ok(plain) = decrypt_packet(recv_key, params, &mut dedup, &buf[..n]) {
+                    if tx.send(plain).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        })
+        .detach();
+        Ok(Self {
+            socket,
+            peer,
+            send_key: cookie.derive_key(false),
+            shared_secret: derived_shared_secret(&cookie, "sosistab3 datagram shared secret"),
+            params,
+            inbound: rx,
+        })
+    }
+
+    /// Obfuscates, encrypts, and sends one datagram.
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        let packet = encrypt_packet(self.send_key, self.params, data);
+        self.socket.send_to(&packet, self.peer).await?;
+        Ok(())
+    }
+
+    /// Waits for and returns the next authenticated, non-replayed datagram.
+    pub async fn recv(&self) -> io::Result<Vec<u8>> {
+        self.inbound
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "datagram socket closed"))
+    }
+
+    /// Wraps this association in an `AsyncRead`/`AsyncWrite` `Pipe`, for callers (like
+    /// `prototest`) that want a uniform stream API over either transport. This is a best-effort
+    /// shim: it frames application writes into datagrams transparently, but — unlike
+    /// `SosistabPipe` over a real stream — it does not retransmit lost datagrams or reorder ones
+    /// that arrive out of order, so it is only as reliable as the underlying network path.
+    pub fn into_pipe(self) -> SosistabDatagramPipe {
+        let peer_addr = self.peer.to_string();
+        SosistabDatagramPipe {
+            inner: self,
+            peer_addr,
+            read_buf: pool::checkout(),
+            recv_fut: None,
+            send_fut: None,
+            send_len: None,
+        }
+    }
+}
+
+/// Binds one shared socket and hands out a fresh [`SosistabDatagram`] for every peer address that
+/// successfully authenticates a packet under `cookie`, mirroring the stream `listener` module's
+/// accept loop.
+pub struct SosistabDatagramListener {
+    new_peers: async_channel::Receiver<SosistabDatagram>,
+}
+
+impl SosistabDatagramListener {
+    pub async fn listen(local: SocketAddr, cookie: Cookie) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        let (new_tx, new_peers) = async_channel::unbounded();
+        smolscale::spawn(Self::demux_loop(socket, cookie, new_tx)).detach();
+        Ok(Self { new_peers })
+    }
+
+    /// Accepts the next never-before-seen peer as a fresh [`SosistabDatagram`].
+    pub async fn accept(&self) -> io::Result<SosistabDatagram> {
+        self.new_peers
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "datagram listener socket closed"))
+    }
+
+    async fn demux_loop(socket: Arc<UdpSocket>, cookie: Cookie, new_tx: async_channel::Sender<SosistabDatagram>) {
+        let recv_key = cookie.derive_key(false);
+        let send_key = cookie.derive_key(true);
+        let params = cookie.params();
+        let mut peers: HashMap<SocketAddr, (async_channel::Sender<Vec<u8>>, Dedup)> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let Ok((n, peer_addr)) = socket.recv_from(&mut buf).await else {
+                break;
+            };
+            let raw = &buf[..n];
+
+            if let Some((tx, dedup)) = peers.get_mut(&peer_addr) {
+                if let Ok(plain) = decrypt_packet(recv_key, params, dedup, raw) {
+                    let _ = tx.try_send(plain);
+                }
+                continue;
+            }
+
+            // An address we haven't seen before: a successful decrypt both proves it holds the
+            // cookie and stands in for a handshake, so we start tracking it as a new association.
+            // A failed decrypt (wrong cookie, or just internet noise) is dropped without any
+            // distinguishing response, so port-scanning this socket looks like silence either way.
+            let mut dedup = Dedup::default();
+            if let Ok(plain) = decrypt_packet(recv_key, params, &mut dedup, raw) {
+                let (tx, rx) = async_channel::unbounded();
+                let _ = tx.try_send(plain);
+                peers.insert(peer_addr, (tx, dedup));
+                let datagram = SosistabDatagram {
+                    socket: socket.clone(),
+                    peer: peer_addr,
+                    send_key,
+                    shared_secret: derived_shared_secret(&cookie, "sosistab3 datagram shared secret"),
+                    params,
+                    inbound: rx,
+                };
+                if new_tx.send(datagram).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// An `AsyncRead`/`AsyncWrite` adapter over a [`SosistabDatagram`]. See
+/// [`SosistabDatagram::into_pipe`] for the reliability caveat.
+#[pin_project]
+pub struct SosistabDatagramPipe {
+    inner: SosistabDatagram,
+    peer_addr: String,
+    read_buf: BytesMut,
+    #[pin]
+    recv_fut: Option<smol::future::Boxed<io::Result<Vec<u8>>>>,
+    #[pin]
+    send_fut: Option<smol::future::Boxed<io::Result<()>>>,
+    /// The plaintext length accepted into the packet currently in flight as `send_fut`, returned
+    /// once it resolves instead of whatever `buf.len()` happens to be on the poll that completes
+    /// it — `buf` is only guaranteed to be the same slice across polls while this is `Some`.
+    send_len: Option<usize>,
+}
+
+impl AsyncWrite for SosistabDatagramPipe {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // One write call becomes one datagram; callers that want large payloads reassembled
+        // losslessly will need a framing layer on top, same as any other datagram transport.
+        let mut this = self.project();
+        if this.send_fut.is_none() {
+            let chunk_len = buf.len().min(MAX_DATAGRAM);
+            let socket = this.inner.socket.clone();
+            let peer = this.inner.peer;
+            let packet = encrypt_packet(this.inner.send_key, this.inner.params, &buf[..chunk_len]);
+            this.send_fut.set(Some(Box::pin(async move {
+                socket.send_to(&packet, peer).await?;
+                Ok(())
+            })));
+            *this.send_len = Some(chunk_len);
+        }
+        let fut = this.send_fut.as_mut().as_pin_mut().unwrap();
+        let result = futures_util::ready!(fut.poll(cx));
+        this.send_fut.set(None);
+        let chunk_len = this.send_len.take().expect("send_fut implies send_len");
+        Poll::Ready(result.map(|()| chunk_len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for SosistabDatagramPipe {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        use bytes::Buf;
+
+        let mut this = self.project();
+        loop {
+            if !this.read_buf.is_empty() {
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+            if this.recv_fut.is_none() {
+                // `recv` borrows `self.inner`, which can't cross the `'static` bound a boxed
+                // future needs; cloning out the channel is cheaper than unsafe lifetime games and
+                // this is already the slow, allocating path relative to the buffered fast path
+                // above.
+                let inbound = this.inner.inbound.clone();
+                this.recv_fut.set(Some(Box::pin(async move {
+                    inbound
+                        .recv()
+                        .await
+                        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "datagram socket closed"))
+                })));
+            }
+            let fut = this.recv_fut.as_mut().as_pin_mut().unwrap();
+            match futures_util::ready!(fut.poll(cx)) {
+                Ok(plain) => {
+                    this.recv_fut.set(None);
+                    this.read_buf.extend_from_slice(&plain);
+                }
+                Err(err) => {
+                    this.recv_fut.set(None);
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl Pipe for SosistabDatagramPipe {
+    fn protocol(&self) -> &str {
+        "sosistab3-datagram"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.peer_addr)
+    }
+
+    fn shared_secret(&self) -> Option<&[u8]> {
+        Some(&self.inner.shared_secret)
+    }
+}
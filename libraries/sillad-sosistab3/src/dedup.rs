@@ -0,0 +1,46 @@
+use std::collections::{HashSet, VecDeque};
+
+/// Bounded replay-detection cache. Used to reject data that has already been seen once under the
+/// same key, most importantly a captured 0-RTT early-data flight being re-sent by an attacker to
+/// re-inject a request.
+///
+/// Entries are evicted in FIFO order once `capacity` is exceeded, trading perfect replay
+/// protection over the life of a cookie for bounded memory; a replay is only accepted once its id
+/// has been evicted, i.e. once at least `capacity` newer ids have been recorded after it.
+pub struct Dedup {
+    seen: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+    capacity: usize,
+}
+
+impl Dedup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `id` and returns `true` if it had not been seen before. Returns `false` if `id` is
+    /// a replay, in which case the caller must not act on the associated data again.
+    pub fn check_and_insert(&mut self, id: [u8; 32]) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        // Generous enough to cover a long burst of reconnects without unbounded growth.
+        Self::new(4096)
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+
+use sillad::{Dialer, Pipe};
+
+use crate::{handshake::client_handshake, Cookie, SosistabPipe};
+
+/// Dials a sosistab3 connection on top of a lower-layer [`Dialer`].
+#[derive(Clone)]
+pub struct SosistabDialer<D: Dialer> {
+    pub lower: D,
+    pub cookie: Cookie,
+    /// Whether [`Self::dial_0rtt`] is allowed to actually send its `early_data` as part of the
+    /// handshake. When `false`, `early_data` passed to `dial_0rtt` is simply dropped rather than
+    /// sent after the fact, since silently downgrading to 1-RTT would defeat the point of calling
+    /// that method over plain [`Dialer::dial`].
+    pub allow_early_data: bool,
+}
+
+impl<D: Dialer> SosistabDialer<D> {
+    pub fn new(lower: D, cookie: Cookie) -> Self {
+        Self {
+            lower,
+            cookie,
+            allow_early_data: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Dialer> Dialer for SosistabDialer<D>
+where
+    D::Output: Pipe,
+{
+    type Output = SosistabPipe<D::Output>;
+
+    async fn dial(&self) -> io::Result<Self::Output> {
+        self.dial_0rtt(None).await
+    }
+}
+
+impl<D: Dialer> SosistabDialer<D>
+where
+    D::Output: Pipe,
+{
+    /// As [`Dialer::dial`], but additionally attempts to send `early_data` as a 0-RTT flight,
+    /// landing it in the peer's hands a full round trip earlier than ordinary application data.
+    pub async fn dial_0rtt(&self, early_data: Option<&[u8]>) -> io::Result<SosistabPipe<D::Output>> {
+        let mut lower = self.lower.dial().await?;
+        let early_data = early_data.filter(|_| self.allow_early_data);
+        let state =
+            client_handshake(&mut lower, &self.cookie, self.cookie.params(), early_data).await?;
+        Ok(SosistabPipe::new(lower, state))
+    }
+}
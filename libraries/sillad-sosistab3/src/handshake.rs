@@ -0,0 +1,158 @@
+use std::io;
+
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use sillad::Pipe;
+
+use crate::{dedup::Dedup, derived_shared_secret, state, state::State, Cookie, ObfsParams};
+
+/// Upper bound, in bytes, on any length-prefixed field read during the handshake (a
+/// `ClientHello`/`ServerHello` JSON blob, or the 0-RTT early-data ciphertext) before the prefix has
+/// been authenticated by anything. Without this, a single unauthenticated connection could send a
+/// length of `u32::MAX` and force a multi-gigabyte allocation ahead of any AEAD check; this is
+/// generous enough for any real handshake message or early-data flight while keeping that
+/// allocation bounded.
+const MAX_HANDSHAKE_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// The client's handshake message: a random nonce (so the server can tell live handshakes apart
+/// from replays of an earlier one) and a flag announcing whether a 0-RTT early-data flight
+/// immediately follows it on the wire.
+#[derive(Serialize, Deserialize)]
+struct ClientHello {
+    nonce: [u8; 32],
+    has_early_data: bool,
+}
+
+/// The server's handshake reply, echoing the client's nonce to prove it was produced for this
+/// specific hello rather than replayed from an earlier one.
+#[derive(Serialize, Deserialize)]
+struct ServerHello {
+    echoed_nonce: [u8; 32],
+}
+
+async fn write_message<P: Pipe>(lower: &mut P, msg: &impl Serialize) -> io::Result<()> {
+    let bytes = serde_json::to_vec(msg).expect("handshake messages always serialize");
+    lower.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    lower.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn read_message<P: Pipe, T: for<'de> Deserialize<'de>>(lower: &mut P) -> io::Result<T> {
+    let len = read_checked_len(lower).await?;
+    let mut buf = vec![0u8; len];
+    lower.read_exact(&mut buf).await?;
+    serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads a big-endian `u32` length prefix and rejects it before it's used to size an allocation,
+/// since at this point in the handshake it hasn't been authenticated by anything yet.
+async fn read_checked_len<P: Pipe>(lower: &mut P) -> io::Result<usize> {
+    let mut len_buf = [0u8; 4];
+    lower.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_HANDSHAKE_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handshake length prefix exceeds the maximum allowed size",
+        ));
+    }
+    Ok(len)
+}
+
+/// Performs the client side of the handshake. When `early_data` is `Some`, its ciphertext is
+/// written to `lower` immediately after the `ClientHello` — the server reads it off the wire
+/// before it sends back its own `ServerHello` (see `server_handshake`), so deferring the write
+/// past this point would leave both sides blocked reading from each other.
+pub(crate) async fn client_handshake<P: Pipe>(
+    lower: &mut P,
+    cookie: &Cookie,
+    params: ObfsParams,
+    early_data: Option<&[u8]>,
+) -> io::Result<State> {
+    let nonce: [u8; 32] = rand::random();
+    write_message(
+        lower,
+        &ClientHello {
+            nonce,
+            has_early_data: early_data.is_some(),
+        },
+    )
+    .await?;
+
+    if let Some(data) = early_data {
+        // `encrypt_early` already length-prefixes its output, which is exactly the framing
+        // `server_handshake` reads back with `read_checked_len`.
+        let ct = state::encrypt_early(cookie.derive_key(false), nonce, data);
+        lower.write_all(&ct).await?;
+    }
+
+    let server_hello: ServerHello = read_message(lower).await?;
+    if server_hello.echoed_nonce != nonce {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server echoed the wrong handshake nonce",
+        ));
+    }
+
+    Ok(State::new(
+        cookie.derive_key(false),
+        cookie.derive_key(true),
+        derived_shared_secret(cookie, "sosistab3 shared secret"),
+        params,
+    ))
+}
+
+/// Performs the server side of the handshake. Returns the resulting `State` plus any early data
+/// the client attached, already decrypted — or `None` if the client sent none, `allow_early_data`
+/// is off, or `dedup` flags the flight as a replay of one already processed. Early-data bytes are
+/// always read off the wire so the stream stays in sync even when they end up discarded.
+pub(crate) async fn server_handshake<P: Pipe>(
+    lower: &mut P,
+    cookie: &Cookie,
+    params: ObfsParams,
+    allow_early_data: bool,
+    dedup: &mut Dedup,
+) -> io::Result<(State, Option<Vec<u8>>)> {
+    let client_hello: ClientHello = read_message(lower).await?;
+
+    let early_data = if client_hello.has_early_data {
+        let len = read_checked_len(lower).await?;
+        let mut ct = vec![0u8; len];
+        lower.read_exact(&mut ct).await?;
+
+        if allow_early_data {
+            // Dedup on the client's handshake nonce rather than the ciphertext: the nonce is
+            // already random and unique per connection, so two legitimate connections that
+            // happen to send the same early-data plaintext (e.g. identical idempotent requests)
+            // produce distinct ciphertexts and distinct ids, while a genuine replay of a captured
+            // flight reuses the same nonce and so the same id.
+            if dedup.check_and_insert(client_hello.nonce) {
+                state::decrypt_early(cookie.derive_key(false), client_hello.nonce, &ct).ok()
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    write_message(
+        lower,
+        &ServerHello {
+            echoed_nonce: client_hello.nonce,
+        },
+    )
+    .await?;
+
+    Ok((
+        State::new(
+            cookie.derive_key(true),
+            cookie.derive_key(false),
+            derived_shared_secret(cookie, "sosistab3 shared secret"),
+            params,
+        ),
+        early_data,
+    ))
+}
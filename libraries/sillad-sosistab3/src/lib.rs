@@ -1,22 +1,30 @@
-use std::{
-    collections::VecDeque,
-    fmt::Debug,
-    io::{ErrorKind, Read, Write},
-    task::Poll,
-};
+use std::{collections::VecDeque, fmt::Debug, io::ErrorKind, task::Poll, time::Duration};
 
+use bytes::{Buf, BytesMut};
 use futures_util::{AsyncRead, AsyncWrite};
-use pin_project::pin_project;
+use pin_project::{pin_project, pinned_drop};
 
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
+use smol::Timer;
 use state::State;
 
+/// Cap on `SosistabPipe::shape_queue`, in bytes: the most application data a shaped pipe will
+/// buffer ahead of the shaper's pacing before `poll_write` starts returning `Pending`, so a
+/// producer faster than `interval_ms` applies backpressure instead of growing memory without
+/// bound. Mirrors the role `mux::INITIAL_WINDOW` plays for mux streams.
+pub(crate) const SHAPE_QUEUE_CAP: usize = 256 * 1024;
+
+pub mod datagram;
 mod dedup;
 pub mod dialer;
 mod handshake;
 pub mod listener;
+pub mod mux;
+mod pool;
 mod state;
+#[cfg(feature = "tokio")]
+mod tokio_compat;
 
 #[derive(Clone, Copy)]
 pub struct Cookie {
@@ -30,6 +38,36 @@ pub struct ObfsParams {
     pub obfs_lengths: bool,
     // whether or not to add delays
     pub obfs_timing: bool,
+    /// When set, enables constant-rate traffic shaping: every outbound cell is exactly
+    /// `shaping.cell_size` bytes on the wire and cells leave every `shaping.interval_ms` plus a
+    /// jitter sample,
+    /// regardless of how much application data is actually queued. Supersedes `obfs_lengths` and
+    /// `obfs_timing` when present.
+    pub shaping: Option<ShapingParams>,
+}
+
+/// Policy for the constant-rate shaper. See [`ObfsParams::shaping`].
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct ShapingParams {
+    /// Fixed size, in bytes, of every cell placed on the wire, header and padding included. Must
+    /// be at least 3 (a 2-byte length header plus one byte of payload/padding); smaller values are
+    /// rejected by `State::new`, which disables shaping rather than building a cell that can't fit
+    /// its own header.
+    pub cell_size: u16,
+    /// Nominal number of milliseconds between cells.
+    pub interval_ms: u32,
+    /// Jitter distribution applied on top of `interval_ms`.
+    pub jitter: JitterKind,
+}
+
+/// A distribution used to jitter the shaper's cell-emission interval.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub enum JitterKind {
+    /// No jitter; cells are spaced exactly `interval_ms` apart.
+    None,
+    /// Exponentially-distributed jitter (memoryless, so it does not introduce predictable gaps)
+    /// with the given mean, in milliseconds.
+    Exponential { mean_ms: u32 },
 }
 
 impl Debug for Cookie {
@@ -78,32 +116,186 @@ impl Cookie {
     pub fn derive_key(&self, is_server: bool) -> [u8; 32] {
         blake3::derive_key(if is_server { "server" } else { "client" }, &self.key)
     }
+
+    /// The obfuscation/shaping policy this cookie was created with.
+    pub fn params(&self) -> ObfsParams {
+        self.params
+    }
+}
+
+/// The value exposed through `Pipe::shared_secret`, distinct from either direction key so that
+/// handing it to a higher layer (e.g. for channel binding) can never leak key material. `context`
+/// distinguishes the stream (`handshake`) and datagram (`datagram`) transports from each other so
+/// the two never derive the same secret from the same cookie.
+pub(crate) fn derived_shared_secret(cookie: &Cookie, context: &str) -> [u8; 32] {
+    blake3::derive_key(context, &cookie.key)
 }
 
 /// An established sosistab3 connection.
-#[pin_project]
+#[pin_project(PinnedDrop)]
 pub struct SosistabPipe<P: Pipe> {
     #[pin]
     lower: P,
     state: State,
 
-    read_buf: VecDeque<u8>,
+    // `read_buf`, `raw_read_buf`, and `to_write_buf` are checked out of `pool` on construction and
+    // returned to it on drop. They're advanced in place (`Buf::advance`) rather than drained, so
+    // consuming the front of a multi-megabyte fragment no longer memmoves the remainder forward.
+    read_buf: BytesMut,
     read_closed: bool,
-    raw_read_buf: Vec<u8>,
+    raw_read_buf: BytesMut,
 
-    to_write_buf: Vec<u8>,
+    to_write_buf: BytesMut,
+    // Set, in unshaped mode, while `to_write_buf` holds ciphertext encrypted from a plaintext
+    // `poll_write` buffer that hasn't fully drained to `lower` yet — the plaintext length to
+    // report once it does, since a caller that (incorrectly) swaps in a different buffer before
+    // completion must not have that buffer's length reported instead.
+    write_plain_len: Option<usize>,
+
+    // Only populated when `state`'s shaper is active: `shape_queue` holds application bytes
+    // waiting to be sliced into the next cell, and `shape_timer` paces cell emission.
+    shape_queue: VecDeque<u8>,
+    shape_timer: Option<Timer>,
 }
 
 impl<P: Pipe> SosistabPipe<P> {
     fn new(lower: P, state: State) -> Self {
+        Self::new_with_pending_write(lower, state, Vec::new())
+    }
+
+    /// As [`Self::new`], but additionally seeds `pending_write` (already wire-ready ciphertext) so
+    /// that it is the first thing flushed out once the pipe starts being polled — `to_write_buf`
+    /// holds raw bytes about to hit the wire in both shaped and unshaped mode, so this works the
+    /// same way regardless. Currently only [`Self::new`] itself uses this with an empty buffer;
+    /// early data is written straight to the lower pipe during the handshake instead (see the
+    /// `handshake` module), since deferring it here would have both ends of the handshake block on
+    /// reading from each other.
+    fn new_with_pending_write(lower: P, mut state: State, pending_write: Vec<u8>) -> Self {
+        let shape_timer = state
+            .shaper_mut()
+            .map(|_| Timer::after(Duration::from_millis(0)));
+        let mut to_write_buf = pool::checkout();
+        to_write_buf.extend_from_slice(&pending_write);
         Self {
             lower,
             state,
-            read_buf: Default::default(),
+            read_buf: pool::checkout(),
             read_closed: false,
-            raw_read_buf: Default::default(),
-            to_write_buf: Default::default(),
+            raw_read_buf: pool::checkout(),
+            to_write_buf,
+            write_plain_len: None,
+            shape_queue: Default::default(),
+            shape_timer,
+        }
+    }
+
+    /// As [`Self::new`], but additionally seeds `read_buf` with `pending_read` (already-decrypted
+    /// plaintext, e.g. an early-data flight validated by the `handshake` module) so it is visible
+    /// to the very first `poll_read` before any bytes have actually arrived from `lower`.
+    fn new_with_pending_read(lower: P, state: State, pending_read: Vec<u8>) -> Self {
+        let mut pipe = Self::new(lower, state);
+        pipe.read_buf.extend_from_slice(&pending_read);
+        pipe
+    }
+}
+
+#[pinned_drop]
+impl<P: Pipe> PinnedDrop for SosistabPipe<P> {
+    fn drop(self: std::pin::Pin<&mut Self>) {
+        let this = self.project();
+        pool::checkin(std::mem::take(this.read_buf));
+        pool::checkin(std::mem::take(this.raw_read_buf));
+        pool::checkin(std::mem::take(this.to_write_buf));
+    }
+}
+
+/// Pumps the shaper: finishes flushing any cell already staged in `to_write_buf`, then each time
+/// `shape_timer` fires stages exactly one more fixed-size cell, sliced off the front of
+/// `shape_queue` (or an all-padding dummy cell if the queue is empty). It keeps doing so until
+/// `lower` or `shape_timer` is pending, so it only ever returns `Pending` or an error.
+///
+/// Note that, like `poll_write` below, idle dummy-cell emission only happens while something is
+/// actively polling this pipe (e.g. a bidirectional copy loop) — there is no background task
+/// driving the pacer on its own.
+#[allow(clippy::too_many_arguments)]
+fn poll_drive_shaper<P: Pipe>(
+    mut lower: std::pin::Pin<&mut P>,
+    cx: &mut std::task::Context<'_>,
+    state: &mut State,
+    to_write_buf: &mut BytesMut,
+    shape_queue: &mut VecDeque<u8>,
+    shape_timer: &mut Option<Timer>,
+) -> Poll<std::io::Result<()>> {
+    loop {
+        if !to_write_buf.is_empty() {
+            match futures_util::ready!(lower.as_mut().poll_write(cx, to_write_buf)) {
+                Ok(n) => {
+                    to_write_buf.advance(n);
+                    if !to_write_buf.is_empty() {
+                        return Poll::Pending;
+                    }
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
         }
+        let timer = shape_timer
+            .as_mut()
+            .expect("shaping active implies a timer");
+        futures_util::ready!(std::pin::Pin::new(timer).poll(cx));
+
+        let cell_size = state
+            .shaper_mut()
+            .expect("shaping active implies a shaper")
+            .cell_size();
+        let take = (cell_size as usize)
+            .saturating_sub(2)
+            .min(shape_queue.len());
+        let payload: Vec<u8> = shape_queue.drain(..take).collect();
+        state.encrypt_cell(&payload, cell_size, to_write_buf);
+
+        let delay_ms = state
+            .shaper_mut()
+            .expect("shaping active implies a shaper")
+            .next_delay_ms();
+        *shape_timer = Some(Timer::after(Duration::from_millis(delay_ms)));
+    }
+}
+
+/// Drains exactly the bytes a caller actually asked to have sent: the in-flight cell already
+/// staged in `to_write_buf`, plus whatever is still sitting in `shape_queue`, sliced into as many
+/// cells as that takes — unlike [`poll_drive_shaper`], this never waits on `shape_timer` to pace
+/// further *dummy* cells, so it always completes in bounded work instead of looping forever.
+pub(crate) fn poll_flush_shaped<P: Pipe>(
+    mut lower: std::pin::Pin<&mut P>,
+    cx: &mut std::task::Context<'_>,
+    state: &mut State,
+    to_write_buf: &mut BytesMut,
+    shape_queue: &mut VecDeque<u8>,
+) -> Poll<std::io::Result<()>> {
+    loop {
+        if !to_write_buf.is_empty() {
+            match futures_util::ready!(lower.as_mut().poll_write(cx, to_write_buf)) {
+                Ok(n) => {
+                    to_write_buf.advance(n);
+                    if !to_write_buf.is_empty() {
+                        return Poll::Pending;
+                    }
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        if shape_queue.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let cell_size = state
+            .shaper_mut()
+            .expect("shaping active implies a shaper")
+            .cell_size();
+        let take = (cell_size as usize)
+            .saturating_sub(2)
+            .min(shape_queue.len());
+        let payload: Vec<u8> = shape_queue.drain(..take).collect();
+        state.encrypt_cell(&payload, cell_size, to_write_buf);
     }
 }
 
@@ -114,12 +306,35 @@ impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        // This implementation here is technically incorrect, if the caller doesn't poll the *same* buffer until completion.
-        // But it seems like it's not possible to be technically correct without spawning a background thread and introducing an extra copy, and this is pretty hot code.
-
         let mut this = self.project();
+
+        if this.state.shaper_mut().is_some() {
+            // Accept bytes into the pacing queue right away, up to its cap; the shaper slices them
+            // into fixed-size, fixed-interval cells independently of how the caller happens to
+            // chunk its writes. This decouples `poll_write` completion from actual wire emission:
+            // a write is reported complete once its bytes are queued, before they reach `lower`.
+            let accept = buf.len().min(SHAPE_QUEUE_CAP.saturating_sub(this.shape_queue.len()));
+            this.shape_queue.extend(buf[..accept].iter().copied());
+            let drive = poll_drive_shaper(
+                this.lower.as_mut(),
+                cx,
+                this.state,
+                this.to_write_buf,
+                this.shape_queue,
+                this.shape_timer,
+            );
+            if accept == 0 {
+                // The queue is already full: report no progress and rely on `poll_drive_shaper`
+                // above having polled `shape_timer`, which registers `cx`'s waker so we're woken
+                // once the next cell drains and makes room.
+                return drive.map(|res| res.map(|()| 0));
+            }
+            return Poll::Ready(Ok(accept));
+        }
+
         if this.to_write_buf.is_empty() {
             this.state.encrypt(buf, this.to_write_buf);
+            *this.write_plain_len = Some(buf.len());
         }
         loop {
             tracing::trace!(bytes_to_write = this.to_write_buf.len(), "polling write");
@@ -132,14 +347,20 @@ impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
                         plain_n = buf.len(),
                         "successfully wrote"
                     );
-                    this.to_write_buf.drain(..n);
+                    this.to_write_buf.advance(n);
                     if this.to_write_buf.is_empty() {
                         tracing::trace!(
                             bytes_to_write = this.to_write_buf.len(),
                             just_wrote = n,
                             "returning Ready from write"
                         );
-                        return Poll::Ready(Ok(buf.len()));
+                        // Report the length of the plaintext that was actually encrypted into the
+                        // buffer that just finished draining, not `buf.len()` from whatever call
+                        // happens to be the one that observes it empty — those differ if the
+                        // caller swapped in a different `buf` before this completed (including the
+                        // very first call after construction, when `to_write_buf` may already hold
+                        // a pending write that predates any `poll_write` call at all).
+                        return Poll::Ready(Ok(this.write_plain_len.take().unwrap_or(0)));
                     }
                 }
                 Err(err) => return Poll::Ready(Err(err)),
@@ -152,10 +373,22 @@ impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::io::Result<()>> {
         let mut this = self.project();
-        if !this.to_write_buf.is_empty() {
+
+        if this.state.shaper_mut().is_some() {
+            // Unlike `poll_write`'s use of `poll_drive_shaper`, flush must actually complete: drain
+            // the in-flight cell and whatever's queued, then return, rather than waiting on
+            // `shape_timer` to keep pacing dummy cells forever.
+            futures_util::ready!(poll_flush_shaped(
+                this.lower.as_mut(),
+                cx,
+                this.state,
+                this.to_write_buf,
+                this.shape_queue,
+            ))?;
+        } else if !this.to_write_buf.is_empty() {
             match futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf)) {
                 Ok(n) => {
-                    this.to_write_buf.drain(..n);
+                    this.to_write_buf.advance(n);
                     if !this.to_write_buf.is_empty() {
                         return Poll::Pending;
                     }
@@ -187,7 +420,10 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
         loop {
             if !this.read_buf.is_empty() || *this.read_closed {
                 tracing::trace!(buf_len = this.read_buf.len(), "reading from the read_buf");
-                return Poll::Ready(this.read_buf.read(buf));
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.advance(n);
+                return Poll::Ready(Ok(n));
             } else {
                 // we reuse buf as a temporary buffer
                 let n = futures_util::ready!(this.lower.as_mut().poll_read(cx, buf));
@@ -198,7 +434,7 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                             *this.read_closed = true;
                             continue;
                         }
-                        this.raw_read_buf.write_all(&buf[..n]).unwrap();
+                        this.raw_read_buf.extend_from_slice(&buf[..n]);
                         tracing::trace!(
                             n,
                             raw_buf_len = this.raw_read_buf.len(),
@@ -206,8 +442,24 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                             "read returned from lower"
                         );
                         // attempt to decrypt in order to fill the read_buf. we decrypt as many fragments as possible until we cannot decrypt anymore. at that point, we would need more fresh data to decrypt more.
+                        let cell_size = this.state.shaper().map(|s| s.cell_size());
                         loop {
-                            match this.state.decrypt(this.raw_read_buf, &mut this.read_buf) {
+                            let outcome = if let Some(cell_size) = cell_size {
+                                this.state.decrypt_cell(this.raw_read_buf, cell_size).map(
+                                    |(consumed, payload)| {
+                                        // Dummy cells carry no payload; they exist purely to keep
+                                        // the on-wire rate constant, so they're dropped here
+                                        // rather than surfaced to the caller.
+                                        if let Some(payload) = payload {
+                                            this.read_buf.extend_from_slice(&payload);
+                                        }
+                                        consumed
+                                    },
+                                )
+                            } else {
+                                this.state.decrypt(this.raw_read_buf, &mut this.read_buf)
+                            };
+                            match outcome {
                                 Ok(result) => {
                                     tracing::trace!(
                                         n,
@@ -215,7 +467,7 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                                         buf_len = this.read_buf.len(),
                                         "decryption is successful"
                                     );
-                                    this.raw_read_buf.drain(..result);
+                                    this.raw_read_buf.advance(result);
                                 }
                                 Err(err) => {
                                     tracing::trace!(
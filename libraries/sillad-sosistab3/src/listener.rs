@@ -0,0 +1,62 @@
+use std::io;
+
+use sillad::{Listener, Pipe};
+
+use crate::{dedup::Dedup, handshake::server_handshake, Cookie, SosistabPipe};
+
+/// Listens for sosistab3 connections on top of a lower-layer [`Listener`].
+pub struct SosistabListener<L: Listener> {
+    lower: L,
+    cookie: Cookie,
+    /// Whether to honor 0-RTT early-data flights from clients, decrypting and delivering them
+    /// through the accepted pipe's first reads. See the `handshake` module for the replay
+    /// protection this relies on.
+    pub allow_early_data: bool,
+    dedup: Dedup,
+}
+
+impl<L: Listener> SosistabListener<L> {
+    pub fn new(lower: L, cookie: Cookie) -> Self {
+        Self {
+            lower,
+            cookie,
+            allow_early_data: false,
+            dedup: Dedup::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: Listener> Listener for SosistabListener<L>
+where
+    L::Output: Pipe,
+{
+    type Output = SosistabPipe<L::Output>;
+
+    async fn accept(&mut self) -> io::Result<Self::Output> {
+        loop {
+            let mut lower = self.lower.accept().await?;
+            match server_handshake(
+                &mut lower,
+                &self.cookie,
+                self.cookie.params(),
+                self.allow_early_data,
+                &mut self.dedup,
+            )
+            .await
+            {
+                Ok((state, early_data)) => {
+                    return Ok(SosistabPipe::new_with_pending_read(
+                        lower,
+                        state,
+                        early_data.unwrap_or_default(),
+                    ));
+                }
+                Err(err) => {
+                    tracing::debug!(?err, "sosistab3 handshake failed, dropping the connection");
+                    continue;
+                }
+            }
+        }
+    }
+}
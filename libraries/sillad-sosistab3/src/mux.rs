@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use bytes::{Buf, BytesMut};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use sillad::Pipe;
+
+/// Initial (and maximum) per-stream flow-control window, in bytes: the most a sender may have
+/// outstanding before the receiver has acknowledged it with a [`FrameType::Credit`] frame.
+const INITIAL_WINDOW: u32 = 256 * 1024;
+/// A stream credits its sender back once it has read this many bytes out of its receive buffer,
+/// rather than after every single read, so credit frames don't dominate the channel.
+const CREDIT_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+/// The type of a framed mux message. Every frame also carries a stream id and a payload (empty
+/// for everything but `Data` and `Credit`).
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameType {
+    /// Opens a new stream; the receiver creates matching local state and surfaces it through
+    /// [`MuxSession::accept_stream`].
+    Syn = 0,
+    /// Carries application bytes.
+    Data = 1,
+    /// Half-closes a stream: no more `Data` will follow, but the sender may still be read from
+    /// in the other direction.
+    Fin = 2,
+    /// Aborts a stream in both directions, e.g. because the application dropped it uncleanly.
+    Rst = 3,
+    /// Replenishes the sender's flow-control window by the `u32` (big-endian) carried as payload.
+    Credit = 4,
+}
+
+impl FrameType {
+    fn from_u8(b: u8) -> io::Result<Self> {
+        Ok(match b {
+            0 => Self::Syn,
+            1 => Self::Data,
+            2 => Self::Fin,
+            3 => Self::Rst,
+            4 => Self::Credit,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown mux frame type")),
+        })
+    }
+}
+
+/// A stream id, a type byte, a `u32` payload length, then the payload — all inside the already
+/// encrypted/obfuscated channel, so this framing only has to worry about multiplexing, not
+/// confidentiality.
+fn encode_frame(stream_id: u32, ty: FrameType, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(9 + payload.len());
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.push(ty as u8);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<(u32, FrameType, Vec<u8>)> {
+    let mut header = [0u8; 9];
+    r.read_exact(&mut header).await?;
+    let stream_id = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let ty = FrameType::from_u8(header[4])?;
+    let len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    Ok((stream_id, ty, payload))
+}
+
+struct StreamShared {
+    read_buf: BytesMut,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    peer_closed: bool,
+    reset: bool,
+    local_unacked: u32,
+    remote_window: u32,
+}
+
+impl StreamShared {
+    fn new() -> Self {
+        Self {
+            read_buf: BytesMut::new(),
+            read_waker: None,
+            write_waker: None,
+            peer_closed: false,
+            reset: false,
+            local_unacked: 0,
+            remote_window: INITIAL_WINDOW,
+        }
+    }
+}
+
+type StreamMap = Arc<Mutex<HashMap<u32, Arc<Mutex<StreamShared>>>>>;
+
+/// A multiplexing layer on top of one already-established [`Pipe`] (typically a
+/// [`crate::SosistabPipe`]), so a single handshake and cookie derivation can carry many
+/// independent logical streams. Confidentiality is entirely the underlying pipe's job; this only
+/// adds stream-id framing, SYN/FIN/RST bookkeeping, and per-stream flow control on top.
+pub struct MuxSession {
+    next_id: AtomicU32,
+    frame_tx: async_channel::Sender<Vec<u8>>,
+    accept_rx: async_channel::Receiver<MuxStream>,
+    streams: StreamMap,
+}
+
+impl MuxSession {
+    /// Wraps `pipe` in a mux session and spawns its background read/write pumps. `is_client`
+    /// picks which side's locally-opened streams get odd ids and which get even ids, so the two
+    /// ends can allocate ids without coordinating over the wire.
+    pub fn new<P: Pipe + Unpin + Send + 'static>(pipe: P, is_client: bool) -> Self {
+        let (read_half, write_half) = pipe.split();
+        let (frame_tx, frame_rx) = async_channel::unbounded::<Vec<u8>>();
+        let (accept_tx, accept_rx) = async_channel::unbounded();
+        let streams: StreamMap = Arc::new(Mutex::new(HashMap::new()));
+
+        smolscale::spawn(Self::writer_loop(write_half, frame_rx)).detach();
+        smolscale::spawn(Self::reader_loop(read_half, streams.clone(), accept_tx, frame_tx.clone())).detach();
+
+        Self {
+            next_id: AtomicU32::new(if is_client { 1 } else { 2 }),
+            frame_tx,
+            accept_rx,
+            streams,
+        }
+    }
+
+    /// Opens a new logical stream, sending its `Syn` immediately.
+    pub fn open_stream(&self) -> MuxStream {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let shared = Arc::new(Mutex::new(StreamShared::new()));
+        self.streams.lock().unwrap().insert(id, shared.clone());
+        let _ = self.frame_tx.try_send(encode_frame(id, FrameType::Syn, &[]));
+        MuxStream {
+            id,
+            shared,
+            frame_tx: self.frame_tx.clone(),
+            streams: self.streams.clone(),
+        }
+    }
+
+    /// Waits for the peer to open the next stream.
+    pub async fn accept_stream(&self) -> io::Result<MuxStream> {
+        self.accept_rx
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))
+    }
+
+    async fn writer_loop<W: AsyncWrite + Unpin>(mut write_half: W, frame_rx: async_channel::Receiver<Vec<u8>>) {
+        while let Ok(encoded) = frame_rx.recv().await {
+            if write_half.write_all(&encoded).await.is_err() || write_half.flush().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn reader_loop<R: AsyncRead + Unpin>(
+        mut read_half: R,
+        streams: StreamMap,
+        accept_tx: async_channel::Sender<MuxStream>,
+        frame_tx: async_channel::Sender<Vec<u8>>,
+    ) {
+        loop {
+            let (stream_id, ty, payload) = match read_frame(&mut read_half).await {
+                Ok(v) => v,
+                Err(err) => {
+                    tracing::debug!(?err, "mux session's underlying pipe closed, tearing down");
+                    break;
+                }
+            };
+            match ty {
+                FrameType::Syn => {
+                    let shared = Arc::new(Mutex::new(StreamShared::new()));
+                    streams.lock().unwrap().insert(stream_id, shared.clone());
+                    let stream = MuxStream {
+                        id: stream_id,
+                        shared,
+                        frame_tx: frame_tx.clone(),
+                        streams: streams.clone(),
+                    };
+                    if accept_tx.send(stream).await.is_err() {
+                        break;
+                    }
+                }
+                FrameType::Data => {
+                    let Some(shared) = streams.lock().unwrap().get(&stream_id).cloned() else {
+                        continue;
+                    };
+                    let mut shared = shared.lock().unwrap();
+                    shared.read_buf.extend_from_slice(&payload);
+                    if let Some(waker) = shared.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                FrameType::Fin => {
+                    let Some(shared) = streams.lock().unwrap().get(&stream_id).cloned() else {
+                        continue;
+                    };
+                    let mut shared = shared.lock().unwrap();
+                    shared.peer_closed = true;
+                    if let Some(waker) = shared.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                FrameType::Rst => {
+                    let Some(shared) = streams.lock().unwrap().get(&stream_id).cloned() else {
+                        continue;
+                    };
+                    let mut shared = shared.lock().unwrap();
+                    shared.reset = true;
+                    if let Some(waker) = shared.read_waker.take() {
+                        waker.wake();
+                    }
+                    if let Some(waker) = shared.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+                FrameType::Credit => {
+                    let Some(shared) = streams.lock().unwrap().get(&stream_id).cloned() else {
+                        continue;
+                    };
+                    if let Ok(bytes) = payload.as_slice().try_into() {
+                        let mut shared = shared.lock().unwrap();
+                        shared.remote_window = shared.remote_window.saturating_add(u32::from_be_bytes(bytes));
+                        if let Some(waker) = shared.write_waker.take() {
+                            waker.wake();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One logical stream inside a [`MuxSession`]. Implements `AsyncRead`/`AsyncWrite` exactly like
+/// any other pipe, so it can be handed to ordinary copy loops.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Mutex<StreamShared>>,
+    frame_tx: async_channel::Sender<Vec<u8>>,
+    streams: StreamMap,
+}
+
+impl MuxStream {
+    /// This stream's id: odd if opened by the client side of the session, even if opened by the
+    /// server side. Both ends of a stream see the same id.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut shared = this.shared.lock().unwrap();
+        if !shared.read_buf.is_empty() {
+            let n = buf.len().min(shared.read_buf.len());
+            buf[..n].copy_from_slice(&shared.read_buf[..n]);
+            shared.read_buf.advance(n);
+            shared.local_unacked += n as u32;
+            if shared.local_unacked >= CREDIT_THRESHOLD {
+                let credit = shared.local_unacked;
+                shared.local_unacked = 0;
+                drop(shared);
+                let _ = this
+                    .frame_tx
+                    .try_send(encode_frame(this.id, FrameType::Credit, &credit.to_be_bytes()));
+            }
+            return Poll::Ready(Ok(n));
+        }
+        if shared.reset {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "mux stream was reset by the peer")));
+        }
+        if shared.peer_closed {
+            return Poll::Ready(Ok(0));
+        }
+        shared.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut shared = this.shared.lock().unwrap();
+        if shared.reset {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "mux stream was reset by the peer")));
+        }
+        if shared.remote_window == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(shared.remote_window as usize);
+        shared.remote_window -= n as u32;
+        drop(shared);
+        // `frame_tx` is unbounded, so the only way `try_send` fails is the session's writer_loop
+        // having exited (its underlying pipe closed) and dropped the receiver — in which case these
+        // bytes are never going anywhere and reporting success would silently lose them.
+        if this
+            .frame_tx
+            .try_send(encode_frame(this.id, FrameType::Data, &buf[..n]))
+            .is_err()
+        {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux session's writer is gone")));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Every accepted write is already handed to the session's single writer task; there is
+        // nothing buffered at the stream level left to flush.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let _ = this.frame_tx.try_send(encode_frame(this.id, FrameType::Fin, &[]));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+        // `Fin` is a half-close that leaves the peer free to keep writing in the other direction,
+        // but dropping this `MuxStream` abandons both directions at once — the entry is gone from
+        // `streams`, so anything the peer sends afterwards would just be dropped on the floor with
+        // no indication anything went wrong. `Rst` aborts both directions explicitly instead.
+        let _ = self.frame_tx.try_send(encode_frame(self.id, FrameType::Rst, &[]));
+    }
+}
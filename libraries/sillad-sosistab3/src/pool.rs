@@ -0,0 +1,33 @@
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+use once_cell::sync::Lazy;
+
+/// Capacity, in bytes, that freshly-allocated pool buffers start out with.
+const DEFAULT_BUF_CAPACITY: usize = 16 * 1024;
+
+/// Pool buffers are discarded rather than recycled once they've grown past this, so one
+/// connection that happens to see a giant fragment can't permanently bloat the pool.
+const MAX_RECYCLED_CAPACITY: usize = DEFAULT_BUF_CAPACITY * 8;
+
+/// A process-wide pool of reusable [`BytesMut`] buffers. `SosistabPipe`'s read/write buffers are
+/// checked out of here on construction and returned on drop, so a long-lived process handling
+/// many short connections reallocates far less than one fresh `Vec`/`BytesMut` per connection.
+static POOL: Lazy<Mutex<Vec<BytesMut>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Checks out an empty buffer, allocating a fresh one (with [`DEFAULT_BUF_CAPACITY`]) if the pool
+/// has nothing to offer.
+pub fn checkout() -> BytesMut {
+    POOL.lock()
+        .unwrap()
+        .pop()
+        .unwrap_or_else(|| BytesMut::with_capacity(DEFAULT_BUF_CAPACITY))
+}
+
+/// Returns `buf` to the pool for reuse by a future `checkout`, clearing its contents first.
+pub fn checkin(mut buf: BytesMut) {
+    buf.clear();
+    if buf.capacity() <= MAX_RECYCLED_CAPACITY {
+        POOL.lock().unwrap().push(buf);
+    }
+}
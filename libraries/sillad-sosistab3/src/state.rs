@@ -0,0 +1,225 @@
+use std::io;
+
+use bytes::BytesMut;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use rand_distr::{Distribution, Exp};
+
+use crate::{JitterKind, ObfsParams, ShapingParams};
+
+/// Length, in bytes, of the Poly1305 tag appended to every encrypted fragment.
+const TAG_LEN: usize = 16;
+
+/// Length of the plaintext header inside a shaped cell: a single `u16` real-length field.
+/// A real length of zero marks the cell as an all-padding dummy.
+const CELL_HEADER_LEN: usize = 2;
+
+/// Per-direction symmetric encryption state for an established [`crate::SosistabPipe`].
+pub struct State {
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_nonce: u64,
+    recv_nonce: u64,
+    shared_secret: [u8; 32],
+    shaper: Option<Shaper>,
+}
+
+impl State {
+    pub fn new(send_key: [u8; 32], recv_key: [u8; 32], shared_secret: [u8; 32], params: ObfsParams) -> Self {
+        Self {
+            send_cipher: ChaCha20Poly1305::new((&send_key).into()),
+            recv_cipher: ChaCha20Poly1305::new((&recv_key).into()),
+            send_nonce: 0,
+            recv_nonce: 0,
+            shared_secret,
+            shaper: params.shaping.and_then(Shaper::new),
+        }
+    }
+
+    pub fn shared_secret(&self) -> &[u8] {
+        &self.shared_secret
+    }
+
+    /// The constant-rate shaping policy negotiated for this connection, if any.
+    pub fn shaper_mut(&mut self) -> Option<&mut Shaper> {
+        self.shaper.as_mut()
+    }
+
+    /// As [`State::shaper_mut`], but without requiring a mutable borrow.
+    pub fn shaper(&self) -> Option<&Shaper> {
+        self.shaper.as_ref()
+    }
+
+    fn send_nonce(&mut self) -> Nonce {
+        let n = self.send_nonce;
+        self.send_nonce += 1;
+        let mut bytes = [0u8; 12];
+        bytes[4..].copy_from_slice(&n.to_be_bytes());
+        Nonce::from(bytes)
+    }
+
+    fn recv_nonce(&mut self) -> Nonce {
+        let n = self.recv_nonce;
+        self.recv_nonce += 1;
+        let mut bytes = [0u8; 12];
+        bytes[4..].copy_from_slice(&n.to_be_bytes());
+        Nonce::from(bytes)
+    }
+
+    /// Encrypts `plain` as a single length-prefixed AEAD fragment, appending it to `out`. `out` is
+    /// typically a pooled buffer borrowed from [`crate::pool`]; this never reallocates it from
+    /// scratch, only grows it in place.
+    pub fn encrypt(&mut self, plain: &[u8], out: &mut BytesMut) {
+        let nonce = self.send_nonce();
+        let ct = self
+            .send_cipher
+            .encrypt(&nonce, plain)
+            .expect("chacha20poly1305 encryption cannot fail");
+        out.extend_from_slice(&(ct.len() as u32).to_be_bytes());
+        out.extend_from_slice(&ct);
+    }
+
+    /// Attempts to decrypt one length-prefixed fragment out of `raw`, appending the plaintext onto
+    /// `out` and returning the number of bytes of `raw` that were consumed — the caller drives
+    /// `raw.advance(result)` rather than draining, so no bytes are memmoved on the hot path.
+    /// Returns an [`io::ErrorKind::WouldBlock`] error when `raw` does not yet contain a whole
+    /// fragment, and [`io::ErrorKind::BrokenPipe`] when the fragment fails to authenticate.
+    pub fn decrypt(&mut self, raw: &[u8], out: &mut BytesMut) -> io::Result<usize> {
+        if raw.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not enough data for a length"));
+        }
+        let len = u32::from_be_bytes(raw[..4].try_into().unwrap()) as usize;
+        if raw.len() < 4 + len {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not enough data for a fragment"));
+        }
+        let nonce = self.recv_nonce();
+        let plain = self
+            .recv_cipher
+            .decrypt(&nonce, &raw[4..4 + len])
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "fragment failed to authenticate"))?;
+        out.extend_from_slice(&plain);
+        Ok(4 + len)
+    }
+
+    /// Encrypts exactly one fixed-size shaped cell for this tick. `payload` is the real plaintext
+    /// to carry (possibly empty, in which case the cell is an all-padding dummy), truncated to fit
+    /// within the shaper's `cell_size` if necessary. The ciphertext, which is always
+    /// `cell_size + TAG_LEN` bytes, is appended to `out`.
+    pub fn encrypt_cell(&mut self, payload: &[u8], cell_size: u16, out: &mut BytesMut) {
+        let cell_size = cell_size as usize;
+        let mut cell = vec![0u8; cell_size];
+        let real_len = payload.len().min(cell_size - CELL_HEADER_LEN);
+        cell[..CELL_HEADER_LEN].copy_from_slice(&(real_len as u16).to_be_bytes());
+        cell[CELL_HEADER_LEN..CELL_HEADER_LEN + real_len].copy_from_slice(&payload[..real_len]);
+        let nonce = self.send_nonce();
+        let ct = self
+            .send_cipher
+            .encrypt(&nonce, cell.as_slice())
+            .expect("chacha20poly1305 encryption cannot fail");
+        out.extend_from_slice(&ct);
+    }
+
+    /// Decrypts exactly one fixed-size shaped cell from the front of `raw`. On success, returns
+    /// the number of bytes of `raw` consumed (always `cell_size + TAG_LEN`) together with the
+    /// cell's real payload, or `None` if it was an all-padding dummy cell that the caller should
+    /// simply drop. Returns [`io::ErrorKind::WouldBlock`] if `raw` does not yet hold a whole cell.
+    pub fn decrypt_cell(&mut self, raw: &[u8], cell_size: u16) -> io::Result<(usize, Option<Vec<u8>>)> {
+        let encrypted_len = cell_size as usize + TAG_LEN;
+        if raw.len() < encrypted_len {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "not enough data for a cell"));
+        }
+        let nonce = self.recv_nonce();
+        let cell = self
+            .recv_cipher
+            .decrypt(&nonce, &raw[..encrypted_len])
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "cell failed to authenticate"))?;
+        let real_len = u16::from_be_bytes(cell[..CELL_HEADER_LEN].try_into().unwrap()) as usize;
+        if real_len == 0 {
+            return Ok((encrypted_len, None));
+        }
+        Ok((encrypted_len, Some(cell[CELL_HEADER_LEN..CELL_HEADER_LEN + real_len].to_vec())))
+    }
+}
+
+/// Derives the 12-byte AEAD nonce used for the early-data channel from the client's 32-byte
+/// handshake nonce, so that two connections sending identical early-data plaintext under the same
+/// static direction key never reuse a (key, nonce) pair.
+fn early_data_nonce(client_nonce: [u8; 32]) -> chacha20poly1305::Nonce {
+    let derived = blake3::derive_key("sosistab3 early data nonce", &client_nonce);
+    *chacha20poly1305::Nonce::from_slice(&derived[..12])
+}
+
+/// Encrypts application data for the 0-RTT early-data channel, which is keyed off the client's
+/// direction key with the per-connection `client_nonce` (the same nonce sent in `ClientHello`)
+/// mixed into the AEAD nonce, since the client sends it before it has anything fresh from the
+/// server to mix in. This trades forward secrecy for availability before the handshake round trip
+/// completes; callers must run `client_nonce` through [`crate::dedup::Dedup`] before acting on the
+/// resulting plaintext.
+pub fn encrypt_early(key: [u8; 32], client_nonce: [u8; 32], plain: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new((&key).into());
+    let ct = cipher
+        .encrypt(&early_data_nonce(client_nonce), plain)
+        .expect("chacha20poly1305 encryption cannot fail");
+    let mut out = Vec::with_capacity(4 + ct.len());
+    out.extend_from_slice(&(ct.len() as u32).to_be_bytes());
+    out.extend_from_slice(&ct);
+    out
+}
+
+/// Decrypts the ciphertext produced by [`encrypt_early`] (the length prefix already stripped by
+/// the caller). Returns an [`io::ErrorKind::BrokenPipe`] error if it fails to authenticate.
+pub fn decrypt_early(key: [u8; 32], client_nonce: [u8; 32], ct: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new((&key).into());
+    cipher
+        .decrypt(&early_data_nonce(client_nonce), ct)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "early data failed to authenticate",
+            )
+        })
+}
+
+/// Drives the constant-rate pacing described by a [`ShapingParams`]: one cell leaves the wire
+/// every `interval_ms` plus a non-negative jitter sample, whether or not the application actually
+/// has data queued.
+pub struct Shaper {
+    params: ShapingParams,
+    exp: Option<Exp<f64>>,
+}
+
+impl Shaper {
+    /// Builds a shaper from `params`, or returns `None` if `cell_size` is too small to hold even
+    /// the length header plus one byte of payload/padding — `encrypt_cell` subtracts
+    /// `CELL_HEADER_LEN` from it, which would otherwise underflow. `params` ultimately comes from a
+    /// cookie string, which may be malformed or hostile, so this is a hard validation rather than
+    /// a debug assertion.
+    fn new(params: ShapingParams) -> Option<Self> {
+        if (params.cell_size as usize) < CELL_HEADER_LEN + 1 {
+            return None;
+        }
+        let exp = match params.jitter {
+            JitterKind::None => None,
+            JitterKind::Exponential { mean_ms } => {
+                Some(Exp::new(1.0 / mean_ms.max(1) as f64).expect("mean_ms is always positive"))
+            }
+        };
+        Some(Self { params, exp })
+    }
+
+    pub fn cell_size(&self) -> u16 {
+        self.params.cell_size
+    }
+
+    /// The delay, in milliseconds, to wait before emitting the next cell: `interval_ms` plus a
+    /// sample drawn from the configured jitter distribution.
+    pub fn next_delay_ms(&self) -> u64 {
+        let jitter = match &self.exp {
+            Some(exp) => exp.sample(&mut rand::thread_rng()) as u64,
+            None => 0,
+        };
+        self.params.interval_ms as u64 + jitter
+    }
+}
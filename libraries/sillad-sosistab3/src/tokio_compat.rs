@@ -0,0 +1,153 @@
+//! Native `tokio::io::AsyncRead`/`AsyncWrite` impls for [`crate::SosistabPipe`], gated behind the
+//! `tokio` feature so the default (futures-only) build pulls in neither tokio nor this module.
+//! Lets the pipe drop directly into hyper/tonic/axum stacks without a `tokio_util::compat`
+//! wrapper and its extra buffered copy. Mirrors the `futures_util` impls in `lib.rs` against the
+//! same fields and the same `poll_drive_shaper`/`poll_flush_shaped`, adapted to `ReadBuf` in place
+//! of `&mut [u8]`.
+
+use std::{
+    io::ErrorKind,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Buf;
+use futures_util::{AsyncRead as _, AsyncWrite as _};
+use sillad::Pipe;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{poll_drive_shaper, poll_flush_shaped, SosistabPipe, SHAPE_QUEUE_CAP};
+
+impl<P: Pipe> AsyncRead for SosistabPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if !this.read_buf.is_empty() || *this.read_closed {
+                let n = buf.remaining().min(this.read_buf.len());
+                buf.put_slice(&this.read_buf[..n]);
+                this.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            // `lower` only speaks the `futures_util` poll interface, so raw bytes are still
+            // pulled through a plain scratch slice regardless of which trait the caller used.
+            let mut scratch = [0u8; 16 * 1024];
+            let n = futures_util::ready!(this.lower.as_mut().poll_read(cx, &mut scratch));
+            match n {
+                Err(err) => return Poll::Ready(Err(err)),
+                Ok(0) => {
+                    *this.read_closed = true;
+                    continue;
+                }
+                Ok(n) => {
+                    this.raw_read_buf.extend_from_slice(&scratch[..n]);
+                    let cell_size = this.state.shaper().map(|s| s.cell_size());
+                    loop {
+                        let outcome = if let Some(cell_size) = cell_size {
+                            this.state.decrypt_cell(this.raw_read_buf, cell_size).map(
+                                |(consumed, payload)| {
+                                    if let Some(payload) = payload {
+                                        this.read_buf.extend_from_slice(&payload);
+                                    }
+                                    consumed
+                                },
+                            )
+                        } else {
+                            this.state.decrypt(this.raw_read_buf, this.read_buf)
+                        };
+                        match outcome {
+                            Ok(consumed) => this.raw_read_buf.advance(consumed),
+                            Err(err) => {
+                                if err.kind() == ErrorKind::BrokenPipe {
+                                    return Poll::Ready(Err(err));
+                                }
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+
+        if this.state.shaper_mut().is_some() {
+            // See the `futures_util::AsyncWrite` impl in `lib.rs` for why this is capped rather
+            // than unbounded.
+            let accept = buf.len().min(SHAPE_QUEUE_CAP.saturating_sub(this.shape_queue.len()));
+            this.shape_queue.extend(buf[..accept].iter().copied());
+            let drive = poll_drive_shaper(
+                this.lower.as_mut(),
+                cx,
+                this.state,
+                this.to_write_buf,
+                this.shape_queue,
+                this.shape_timer,
+            );
+            if accept == 0 {
+                return drive.map(|res| res.map(|()| 0));
+            }
+            return Poll::Ready(Ok(accept));
+        }
+
+        if this.to_write_buf.is_empty() {
+            this.state.encrypt(buf, this.to_write_buf);
+            *this.write_plain_len = Some(buf.len());
+        }
+        loop {
+            match futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf)) {
+                Ok(n) => {
+                    this.to_write_buf.advance(n);
+                    if this.to_write_buf.is_empty() {
+                        return Poll::Ready(Ok(this.write_plain_len.take().unwrap_or(0)));
+                    }
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut this = self.project();
+
+        if this.state.shaper_mut().is_some() {
+            // Unlike `poll_write`'s use of `poll_drive_shaper`, flush must actually complete: drain
+            // the in-flight cell and whatever's queued, then return, rather than waiting on
+            // `shape_timer` to keep pacing dummy cells forever.
+            futures_util::ready!(poll_flush_shaped(
+                this.lower.as_mut(),
+                cx,
+                this.state,
+                this.to_write_buf,
+                this.shape_queue,
+            ))?;
+        } else if !this.to_write_buf.is_empty() {
+            match futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf)) {
+                Ok(n) => {
+                    this.to_write_buf.advance(n);
+                    if !this.to_write_buf.is_empty() {
+                        return Poll::Pending;
+                    }
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        this.lower.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().lower.poll_close(cx)
+    }
+}